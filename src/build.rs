@@ -1,5 +1,6 @@
+use std::collections::{BTreeMap, HashSet};
 use std::fs::{self, File};
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 use std::path::Path;
 
 use lxd::{Container, Image, Location};
@@ -8,6 +9,17 @@ use tempdir::TempDir;
 
 use {Config, Manifest, Sha384, Source};
 
+mod cache;
+mod error;
+mod retry;
+mod sign;
+mod verify;
+
+use self::cache::{copy_dir_all, Cache};
+pub use self::error::BuildError;
+pub use self::retry::{retry, RetryConfig};
+pub use self::verify::{verify, VerifyReport};
+
 /// A temporary structure used to generate a unique build environment
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 struct BuildEnvironmentConfig {
@@ -17,10 +29,67 @@ struct BuildEnvironmentConfig {
     pub prepare: Vec<Vec<String>>,
 }
 
-fn prepare(config: &Config, location: &Location) -> io::Result<String> {
+/// One leg of a build matrix: a named LXC base to build against, with an
+/// optional override of the top-level `prepare` commands. A `Config` with
+/// no `matrix` is treated as the degenerate, single-entry matrix built by
+/// `matrix_legs`.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct MatrixLeg {
+    /// Name of this leg, used for its artifact subdirectory and container names
+    pub name: String,
+    /// The LXC base to use for this leg
+    pub base: String,
+    /// Overrides the top-level `prepare` commands for this leg, if set
+    #[serde(default)]
+    pub prepare_overrides: Option<Vec<Vec<String>>>,
+}
+
+/// Returns `config`'s build matrix, falling back to a single leg built from
+/// `config.base`/`config.prepare` when `config.matrix` is empty so the
+/// single-base case is just the degenerate one-entry matrix.
+fn matrix_legs(config: &Config) -> Vec<MatrixLeg> {
+    if config.matrix.is_empty() {
+        vec![MatrixLeg {
+            name: config.name.clone(),
+            base: config.base.clone(),
+            prepare_overrides: None,
+        }]
+    } else {
+        config.matrix.clone()
+    }
+}
+
+/// Returns `true` if `name` is safe to use as a single path component (its
+/// own artifact subdirectory). `leg.name` comes from the build config inside
+/// the downloaded, untrusted source tree, so it must not be empty or able to
+/// escape the directory it's joined into.
+pub(crate) fn valid_leg_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && !name.contains('\\') && name != ".." && name != "."
+}
+
+/// Checks that every leg has a safe, unique name, since the name doubles as
+/// both the artifact subdirectory and the key into `manifests`, and a
+/// duplicate would silently overwrite an earlier leg's output with a later
+/// one's.
+fn check_unique_leg_names(legs: &[MatrixLeg]) -> Result<(), BuildError> {
+    let mut seen = HashSet::new();
+    for leg in legs {
+        if !valid_leg_name(&leg.name) {
+            return Err(BuildError::InvalidMatrixLeg(leg.name.clone()));
+        }
+        if !seen.insert(leg.name.as_str()) {
+            return Err(BuildError::DuplicateMatrixLeg(leg.name.clone()));
+        }
+    }
+    Ok(())
+}
+
+fn prepare(config: &Config, location: &Location, leg: &MatrixLeg) -> io::Result<String> {
+    let prepare_commands = leg.prepare_overrides.as_ref().unwrap_or(&config.prepare);
+
     let build_json = serde_json::to_string(&BuildEnvironmentConfig {
-        base: config.base.clone(),
-        prepare: config.prepare.clone(),
+        base: leg.base.clone(),
+        prepare: prepare_commands.clone(),
     }).map_err(|err| {
         io::Error::new(io::ErrorKind::Other, err)
     })?;
@@ -33,15 +102,19 @@ fn prepare(config: &Config, location: &Location) -> io::Result<String> {
         io::Error::new(io::ErrorKind::Other, err)
     })?;
 
-    let build_image = format!("buildchain-{}-{}", config.name, build_sha_str.trim_matches('"'));
+    let build_image = format!("buildchain-{}-{}", leg.name, build_sha_str.trim_matches('"'));
 
+    // Always ask the LXD backend, rather than trusting a local record that
+    // the image was once published: the image can be pruned out-of-band,
+    // and a local cache directory has no way to know which remote it was
+    // last checked against.
     if Image::new(location.clone(), &build_image).is_ok() {
         println!("Build environment cached as {}", build_image);
     } else {
-        println!("Create container {} from {}", build_image, config.base);
-        let mut container = Container::new(location.clone(), &build_image, &config.base)?;
+        println!("Create container {} from {}", build_image, leg.base);
+        let mut container = retry_if_remote(location, &config.retry, || Container::new(location.clone(), &build_image, &leg.base))?;
 
-        for command in config.prepare.iter() {
+        for command in prepare_commands.iter() {
             let mut args = vec![];
             for arg in command.iter() {
                 args.push(arg.as_str());
@@ -55,23 +128,30 @@ fn prepare(config: &Config, location: &Location) -> io::Result<String> {
         let snapshot = container.snapshot(&build_image)?;
 
         println!("Publish build environment as {}", build_image);
-        snapshot.publish(&build_image)?;
+        retry_if_remote(location, &config.retry, || snapshot.publish(&build_image))?;
     }
 
     Ok(build_image)
 }
 
-fn run<P: AsRef<Path>, Q: AsRef<Path>>(config: &Config, location: &Location, build_image: &str, source_time: u64, source_path: P, temp_path: Q) -> io::Result<()> {
+fn run<P: AsRef<Path>, Q: AsRef<Path>>(config: &Config, location: &Location, leg: &MatrixLeg, build_image: &str, source_time: u64, source_path: P, temp_path: Q) -> io::Result<()> {
     let source_path = source_path.as_ref();
     let temp_path = temp_path.as_ref();
 
-    let container_name = format!("buildchain-{}-{}", config.name, source_time);
+    // Keep the single-base (degenerate one-leg matrix) case named exactly as
+    // it always was: `leg.name` only adds information once it differs from
+    // `config.name`.
+    let container_name = if leg.name == config.name {
+        format!("buildchain-{}-{}", config.name, source_time)
+    } else {
+        format!("buildchain-{}-{}-{}", config.name, leg.name, source_time)
+    };
 
     println!("Create container {} from {}", container_name, build_image);
-    let mut container = Container::new(location.clone(), &container_name, build_image)?;
+    let mut container = retry_if_remote(location, &config.retry, || Container::new(location.clone(), &container_name, build_image))?;
 
     println!("Push source");
-    container.push(source_path, "/root", true)?;
+    retry_if_remote(location, &config.retry, || container.push(source_path, "/root", true))?;
 
     for command in config.build.iter() {
         let mut args = Vec::new();
@@ -97,29 +177,41 @@ fn run<P: AsRef<Path>, Q: AsRef<Path>>(config: &Config, location: &Location, bui
     }
 
     println!("Pull artifacts");
-    container.pull("/root/artifacts", temp_path, true)?;
+    retry_if_remote(location, &config.retry, || container.pull("/root/artifacts", temp_path, true))?;
 
     Ok(())
 }
 
+/// Retries `op` with `config`'s backoff only when `location` is remote;
+/// local LXD operations do not see the transient network failures this
+/// guards against, so they run unwrapped.
+fn retry_if_remote<T, F: FnMut() -> io::Result<T>>(location: &Location, config: &RetryConfig, mut op: F) -> io::Result<T> {
+    match *location {
+        Location::Remote(_) => retry(config, op),
+        Location::Local => op(),
+    }
+}
+
 pub struct BuildArguments<'a> {
     pub config_path: &'a str,
     pub output_path: &'a str,
     pub remote_opt: Option<&'a str>,
     pub source_url: &'a str,
     pub source_kind: &'a str,
+    pub cache_dir: Option<&'a str>,
+    pub secret_key_path: Option<&'a str>,
 }
 
-pub fn build<'a>(args: BuildArguments<'a>) -> Result<(), String> {
+pub fn build<'a>(args: BuildArguments<'a>) -> Result<(), BuildError> {
     let config_path = args.config_path;
 
-    let temp_dir = match TempDir::new("buildchain") {
-        Ok(dir) => dir,
-        Err(err) => {
-            return Err(format!("failed to create temporary directory: {}", err));
-        }
+    let cache = match args.cache_dir {
+        Some(cache_dir) => Some(Cache::new(cache_dir).map_err(BuildError::Io)?),
+        None => None,
     };
 
+    let temp_dir = TempDir::new("buildchain").map_err(BuildError::Io)?;
+
     let source = Source {
         kind: args.source_kind.to_string(),
         url: args.source_url.to_string()
@@ -127,35 +219,36 @@ pub fn build<'a>(args: BuildArguments<'a>) -> Result<(), String> {
 
     let source_path = temp_dir.path().join("source");
 
-    let source_time = match source.download(&source_path) {
-        Ok(time) => time,
-        Err(err) => {
-            return Err(format!("failed to download source {:?}: {}", source, err));
-        }
+    let cached_source = match cache {
+        Some(ref cache) => cache.cached_source(&source.kind, &source.url).map_err(BuildError::Io)?,
+        None => None,
     };
 
-    let mut file = match File::open(&source_path.join(&config_path)) {
-        Ok(file) => file,
-        Err(err) => {
-            return Err(format!("failed to open config {}: {}", config_path, err));
+    let source_time = match cached_source {
+        Some((cached_source_path, source_time)) => {
+            println!("buildchain: reusing cached source for {:?}", source);
+            copy_dir_all(&cached_source_path, &source_path).map_err(BuildError::Io)?;
+            source_time
         }
-    };
+        None => {
+            let source_time = retry(&RetryConfig::default(), || source.download(&source_path))
+                .map_err(BuildError::Download)?;
 
-    let mut string = String::new();
-    match file.read_to_string(&mut string) {
-        Ok(_) => (),
-        Err(err) => {
-            return Err(format!("failed to read config {}: {}", config_path, err));
-        }
-    }
+            if let Some(ref cache) = cache {
+                cache.store_source(&source.kind, &source.url, source_time, &source_path).map_err(BuildError::Io)?;
+            }
 
-    let config = match serde_json::from_str::<Config>(&string) {
-        Ok(config) => config,
-        Err(err) => {
-            return Err(format!("failed to parse config {}: {}", config_path, err));
+            source_time
         }
     };
 
+    let mut file = File::open(&source_path.join(&config_path)).map_err(BuildError::ConfigRead)?;
+
+    let mut string = String::new();
+    file.read_to_string(&mut string).map_err(BuildError::ConfigRead)?;
+
+    let config = serde_json::from_str::<Config>(&string).map_err(BuildError::ConfigParse)?;
+
     let location = if let Some(remote) = args.remote_opt {
         println!("buildchain: building {} on {}", config.name, remote);
         Location::Remote(remote.to_string())
@@ -164,50 +257,144 @@ pub fn build<'a>(args: BuildArguments<'a>) -> Result<(), String> {
         Location::Local
     };
 
-    let build_image = match prepare(&config, &location) {
-        Ok(build_image) => build_image,
-        Err(err) => {
-            return Err(format!("failed to prepare config {}: {}", config_path, err));
-        }
+    let cached_artifacts = match cache {
+        Some(ref cache) => cache.cached_artifacts(&config.name, source_time).map_err(BuildError::Io)?,
+        None => None,
     };
 
-    match run(&config, &location, &build_image, source_time, &source_path, &temp_dir.path()) {
-        Ok(()) => (),
-        Err(err) => {
-            return Err(format!("failed to run config {}: {}", config_path, err));
-        }
-    }
+    if let Some(cached_artifacts) = cached_artifacts {
+        println!("buildchain: reusing cached build for {}-{}", config.name, source_time);
+        copy_dir_all(&cached_artifacts, temp_dir.path()).map_err(BuildError::Io)?;
+    } else {
+        let legs = matrix_legs(&config);
+        check_unique_leg_names(&legs)?;
+        let mut manifests = BTreeMap::new();
+
+        for leg in &legs {
+            println!("buildchain: building matrix leg {}", leg.name);
+
+            let leg_dir = temp_dir.path().join("artifacts").join(&leg.name);
+            fs::create_dir_all(&leg_dir).map_err(BuildError::Io)?;
+
+            let build_image = prepare(&config, &location, leg).map_err(BuildError::Prepare)?;
+
+            run(&config, &location, leg, &build_image, source_time, &source_path, &leg_dir)
+                .map_err(BuildError::Run)?;
+
+            let manifest = Manifest::new(source_time, leg_dir.join("artifacts"))
+                .map_err(BuildError::Manifest)?;
 
-    let manifest = match Manifest::new(source_time, temp_dir.path().join("artifacts")) {
-        Ok(manifest) => manifest,
-        Err(err) => {
-            return Err(format!("failed to generate manifest: {}", err));
+            manifests.insert(leg.name.clone(), manifest);
         }
-    };
 
-    match File::create(temp_dir.path().join("manifest.json")) {
-        Ok(mut file) => {
-            if let Err(err) = serde_json::to_writer_pretty(&mut file, &manifest) {
-                return Err(format!("failed to write manifest: {}", err));
-            }
-            if let Err(err) = file.sync_all() {
-                return Err(format!("failed to sync manifest: {}", err));
-            }
-        },
-        Err(err) => {
-            return Err(format!("failed to create manifest: {}", err));
+        let manifest_json = serde_json::to_vec_pretty(&manifests).map_err(|err| {
+            BuildError::Manifest(io::Error::new(io::ErrorKind::Other, err))
+        })?;
+
+        let mut file = File::create(temp_dir.path().join("manifest.json")).map_err(BuildError::Manifest)?;
+        file.write_all(&manifest_json).map_err(BuildError::Manifest)?;
+        file.sync_all().map_err(BuildError::Manifest)?;
+
+        if let Some(ref cache) = cache {
+            // Cache only `artifacts/` and `manifest.json`, not the whole temp
+            // dir: it also holds `source/`, which `cached_source` already
+            // caches separately, so including it here would store a second
+            // full copy of the downloaded source in every artifacts entry.
+            let cache_payload_dir = TempDir::new("buildchain-cache").map_err(BuildError::Io)?;
+            copy_dir_all(&temp_dir.path().join("artifacts"), &cache_payload_dir.path().join("artifacts")).map_err(BuildError::Io)?;
+            fs::copy(temp_dir.path().join("manifest.json"), cache_payload_dir.path().join("manifest.json")).map_err(BuildError::Io)?;
+            cache.store_artifacts(&config.name, source_time, cache_payload_dir.path()).map_err(BuildError::Io)?;
         }
     }
 
+    // Sign (or re-sign) `manifest.json` regardless of whether it came from a
+    // fresh build or the artifact cache, so a cache hit can never ship a
+    // manifest signed with a stale or different key than the one requested
+    // for this run.
+    if let Some(secret_key_path) = args.secret_key_path {
+        let manifest_json = fs::read(temp_dir.path().join("manifest.json")).map_err(BuildError::Manifest)?;
+        let secret_key = fs::read(secret_key_path).map_err(BuildError::Manifest)?;
+        let signature = sign::sign(&secret_key, &manifest_json).map_err(BuildError::Manifest)?;
+
+        let mut sig_file = File::create(temp_dir.path().join("manifest.json.sig")).map_err(BuildError::Manifest)?;
+        sig_file.write_all(&signature).map_err(BuildError::Manifest)?;
+        sig_file.sync_all().map_err(BuildError::Manifest)?;
+    }
+
     let temp_path = temp_dir.into_path();
-    match fs::rename(&temp_path, &args.output_path) {
-        Ok(()) => {
-            println!("buildchain: placed results in {}", args.output_path);
-        },
-        Err(err) => {
-            return Err(format!("failed to move temporary directory {}: {}", temp_path.display(), err));
+    fs::rename(&temp_path, &args.output_path).map_err(BuildError::Io)?;
+    println!("buildchain: placed results in {}", args.output_path);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leg(name: &str) -> MatrixLeg {
+        MatrixLeg { name: name.to_string(), base: "base".to_string(), prepare_overrides: None }
+    }
+
+    fn test_config(matrix: Vec<MatrixLeg>) -> Config {
+        Config {
+            name: "widget".to_string(),
+            base: "ubuntu:18.04".to_string(),
+            prepare: vec![],
+            build: vec![],
+            publish: vec![],
+            matrix: matrix,
+            retry: RetryConfig::default(),
         }
     }
 
-    Ok(())
+    #[test]
+    fn matrix_legs_defaults_to_a_single_leg_from_name_and_base() {
+        let config = test_config(vec![]);
+        let legs = matrix_legs(&config);
+        assert_eq!(legs, vec![MatrixLeg {
+            name: "widget".to_string(),
+            base: "ubuntu:18.04".to_string(),
+            prepare_overrides: None,
+        }]);
+        assert_eq!(legs[0].base, "ubuntu:18.04");
+    }
+
+    #[test]
+    fn matrix_legs_returns_the_configured_matrix_unchanged() {
+        let config = test_config(vec![leg("a"), leg("b")]);
+        assert_eq!(matrix_legs(&config), vec![leg("a"), leg("b")]);
+    }
+
+    #[test]
+    fn valid_leg_name_rejects_empty_and_path_like_names() {
+        assert!(valid_leg_name("stable"));
+        assert!(!valid_leg_name(""));
+        assert!(!valid_leg_name("."));
+        assert!(!valid_leg_name(".."));
+        assert!(!valid_leg_name("a/b"));
+        assert!(!valid_leg_name("a\\b"));
+        assert!(!valid_leg_name("../../../tmp/evil"));
+    }
+
+    #[test]
+    fn check_unique_leg_names_accepts_distinct_valid_names() {
+        assert!(check_unique_leg_names(&[leg("a"), leg("b")]).is_ok());
+    }
+
+    #[test]
+    fn check_unique_leg_names_rejects_duplicates() {
+        match check_unique_leg_names(&[leg("a"), leg("a")]) {
+            Err(BuildError::DuplicateMatrixLeg(ref name)) => assert_eq!(name, "a"),
+            other => panic!("expected DuplicateMatrixLeg, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_unique_leg_names_rejects_unsafe_names() {
+        match check_unique_leg_names(&[leg("../../../../tmp/evil")]) {
+            Err(BuildError::InvalidMatrixLeg(_)) => (),
+            other => panic!("expected InvalidMatrixLeg, got {:?}", other),
+        }
+    }
 }
\ No newline at end of file
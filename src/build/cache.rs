@@ -0,0 +1,268 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+use serde_json;
+
+use Sha384;
+
+/// A local, on-disk, crash-safe cache for downloaded sources and manifest artifacts.
+pub struct Cache {
+    directory: PathBuf,
+}
+
+impl Cache {
+    /// Open (creating if necessary) a cache rooted at `directory`.
+    pub fn new<P: AsRef<Path>>(directory: P) -> io::Result<Cache> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)?;
+        Ok(Cache { directory })
+    }
+
+    fn source_path(&self, key: &str) -> PathBuf {
+        self.directory.join("sources").join(key)
+    }
+
+    fn source_meta_path(&self, key: &str) -> PathBuf {
+        self.directory.join("sources").join(format!("{}.meta", key))
+    }
+
+    fn artifacts_path(&self, key: &str) -> PathBuf {
+        self.directory.join("artifacts").join(key)
+    }
+
+    /// Returns the cached source tree and its recorded `source_time` for
+    /// `kind`/`url`, if one was stored by a previous invocation. The key is
+    /// derived from `kind`/`url` alone, since those are known before a
+    /// download is even attempted, unlike `source_time`, which is download's
+    /// return value.
+    pub fn cached_source(&self, kind: &str, url: &str) -> io::Result<Option<(PathBuf, u64)>> {
+        let key = source_key(kind, url)?;
+
+        let path = self.source_path(&key);
+        if !path.is_dir() {
+            return Ok(None);
+        }
+
+        let meta = match fs::read_to_string(self.source_meta_path(&key)) {
+            Ok(meta) => meta,
+            Err(_) => return Ok(None),
+        };
+
+        match meta.trim().parse() {
+            Ok(source_time) => Ok(Some((path, source_time))),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Stores `source_path` as the cached source tree for `kind`/`url`,
+    /// recording `source_time` alongside it so a later `cached_source` call
+    /// can skip downloading entirely.
+    pub fn store_source<P: AsRef<Path>>(&self, kind: &str, url: &str, source_time: u64, source_path: P) -> io::Result<()> {
+        let key = source_key(kind, url)?;
+        let dest = self.source_path(&key);
+        if !dest.is_dir() {
+            let parent = dest.parent().expect("source cache path has a parent");
+            fs::create_dir_all(parent)?;
+
+            let tmp = parent.join(format!("{}.tmp", key));
+            if tmp.exists() {
+                fs::remove_dir_all(&tmp)?;
+            }
+
+            match copy_dir_all(source_path.as_ref(), &tmp) {
+                Ok(()) => fs::rename(&tmp, &dest)?,
+                Err(err) => {
+                    let _ = fs::remove_dir_all(&tmp);
+                    return Err(err);
+                }
+            }
+        }
+
+        write_atomic(&self.source_meta_path(&key), source_time.to_string().as_bytes())
+    }
+
+    /// Returns the cached manifest and artifacts directory for
+    /// `name`/`source_time`, if a previous invocation completed a build for
+    /// this exact source. The key is hashed the same way as `source_key`,
+    /// since `name` comes from the (untrusted) downloaded build config.
+    pub fn cached_artifacts(&self, name: &str, source_time: u64) -> io::Result<Option<PathBuf>> {
+        let path = self.artifacts_path(&artifacts_key(name, source_time)?);
+        Ok(if path.is_dir() { Some(path) } else { None })
+    }
+
+    /// Stores `artifacts_path` (expected to contain `manifest.json` and an
+    /// `artifacts/` directory) as the cached manifest artifacts for
+    /// `name`/`source_time`.
+    pub fn store_artifacts<P: AsRef<Path>>(&self, name: &str, source_time: u64, artifacts_path: P) -> io::Result<()> {
+        let key = artifacts_key(name, source_time)?;
+        let dest = self.artifacts_path(&key);
+        if dest.is_dir() {
+            return Ok(());
+        }
+
+        let parent = dest.parent().expect("artifacts cache path has a parent");
+        fs::create_dir_all(parent)?;
+
+        let tmp = parent.join(format!("{}.tmp", key));
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+
+        let result = copy_dir_all(artifacts_path.as_ref(), &tmp);
+        match result {
+            Ok(()) => fs::rename(&tmp, &dest),
+            Err(err) => {
+                let _ = fs::remove_dir_all(&tmp);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Hashes `data` into a cache key that is always a single, safe path
+/// component, regardless of what characters `data` contains. Needed because
+/// the values fed into cache keys (`kind`/`url`, or a build config's `name`)
+/// come from untrusted config/source content, not from us.
+fn hash_key(data: &str) -> io::Result<String> {
+    let sha = Sha384::new(&mut data.as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    let sha_str = serde_json::to_string(&sha).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    Ok(sha_str.trim_matches('"').to_string())
+}
+
+/// Derives a stable cache key from a source's `kind`/`url`, which (unlike
+/// `source_time`) are both known before a download is attempted.
+fn source_key(kind: &str, url: &str) -> io::Result<String> {
+    hash_key(&format!("{}\n{}", kind, url))
+}
+
+/// Derives a stable cache key from a build's `name`/`source_time`.
+fn artifacts_key(name: &str, source_time: u64) -> io::Result<String> {
+    hash_key(&format!("{}\n{}", name, source_time))
+}
+
+/// Writes `data` to `path` crash-safely: the data is written to `<path>.tmp`
+/// with `create_new(true)` (and, on unix, `mode(0o600)`), flushed with
+/// `sync_data`, and only then `rename`d into place. The tmp file is removed
+/// on any error so a failed write never leaves stray state behind.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let result = (|| -> io::Result<()> {
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_data()
+    })();
+
+    match result {
+        Ok(()) => fs::rename(&tmp_path, path),
+        Err(err) => {
+            let _ = fs::remove_file(&tmp_path);
+            Err(err)
+        }
+    }
+}
+
+pub fn copy_dir_all(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let dest_path = dst.join(entry.file_name());
+        if file_type.is_dir() {
+            copy_dir_all(&entry.path(), &dest_path)?;
+        } else {
+            fs::copy(entry.path(), &dest_path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn cached_source_is_a_miss_before_anything_is_stored() {
+        let cache = Cache::new(TempDir::new("cache").unwrap().into_path()).unwrap();
+        assert!(cache.cached_source("git", "https://example.com/repo.git").unwrap().is_none());
+    }
+
+    #[test]
+    fn store_source_then_cached_source_round_trips() {
+        let cache = Cache::new(TempDir::new("cache").unwrap().into_path()).unwrap();
+
+        let source_dir = TempDir::new("source").unwrap();
+        fs::write(source_dir.path().join("file.txt"), b"hello").unwrap();
+
+        cache.store_source("git", "https://example.com/repo.git", 42, source_dir.path()).unwrap();
+
+        let (cached_path, source_time) = cache.cached_source("git", "https://example.com/repo.git").unwrap().unwrap();
+        assert_eq!(source_time, 42);
+        assert_eq!(fs::read(cached_path.join("file.txt")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn cached_source_keys_are_independent_per_kind_and_url() {
+        let cache = Cache::new(TempDir::new("cache").unwrap().into_path()).unwrap();
+
+        let source_dir = TempDir::new("source").unwrap();
+        cache.store_source("git", "https://example.com/a.git", 1, source_dir.path()).unwrap();
+
+        assert!(cache.cached_source("git", "https://example.com/b.git").unwrap().is_none());
+        assert!(cache.cached_source("tar", "https://example.com/a.git").unwrap().is_none());
+    }
+
+    #[test]
+    fn cached_artifacts_is_a_miss_before_anything_is_stored() {
+        let cache = Cache::new(TempDir::new("cache").unwrap().into_path()).unwrap();
+        assert!(cache.cached_artifacts("widget", 42).unwrap().is_none());
+    }
+
+    #[test]
+    fn store_artifacts_then_cached_artifacts_round_trips() {
+        let cache = Cache::new(TempDir::new("cache").unwrap().into_path()).unwrap();
+
+        let artifacts_dir = TempDir::new("artifacts").unwrap();
+        fs::write(artifacts_dir.path().join("manifest.json"), b"{}").unwrap();
+
+        cache.store_artifacts("widget", 42, artifacts_dir.path()).unwrap();
+
+        let cached_path = cache.cached_artifacts("widget", 42).unwrap().unwrap();
+        assert_eq!(fs::read(cached_path.join("manifest.json")).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn artifact_keys_are_safe_path_components_even_for_unsafe_names() {
+        let key = artifacts_key("../../../../tmp/evil", 1).unwrap();
+        assert!(!key.contains('/'));
+        assert!(!key.contains('\\'));
+        assert!(!key.contains(".."));
+    }
+
+    #[test]
+    fn write_atomic_creates_the_file_and_leaves_no_tmp_behind() {
+        let dir = TempDir::new("write_atomic").unwrap();
+        let path = dir.path().join("entry");
+
+        write_atomic(&path, b"data").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+        assert!(!path.with_extension("tmp").exists());
+    }
+}
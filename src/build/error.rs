@@ -0,0 +1,71 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use serde_json;
+
+/// Everything that can go wrong while building a config, with the
+/// underlying error preserved so callers can match on the failing stage
+/// and the full causal chain can still be printed.
+#[derive(Debug)]
+pub enum BuildError {
+    /// The source tree failed to download.
+    Download(io::Error),
+    /// The build config file could not be read.
+    ConfigRead(io::Error),
+    /// The build config file could not be parsed as JSON.
+    ConfigParse(serde_json::Error),
+    /// The build environment (container image) failed to prepare.
+    Prepare(io::Error),
+    /// The build itself (push, build commands, publish, pull) failed.
+    Run(io::Error),
+    /// The output manifest failed to generate, write, or sign.
+    Manifest(io::Error),
+    /// Any other I/O failure (temp directory, cache, final rename, ...).
+    Io(io::Error),
+    /// The build matrix has two or more legs with the same name.
+    DuplicateMatrixLeg(String),
+    /// A matrix leg's name is empty or unsafe to use as a path component.
+    InvalidMatrixLeg(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            // These each wrap an underlying error that `source()` also
+            // exposes; leave it out of the message so `print_error_chain`
+            // is the only place that ever prints it.
+            BuildError::Download(_) => write!(f, "failed to download source"),
+            BuildError::ConfigRead(_) => write!(f, "failed to read config"),
+            BuildError::ConfigParse(_) => write!(f, "failed to parse config"),
+            BuildError::Prepare(_) => write!(f, "failed to prepare build environment"),
+            BuildError::Run(_) => write!(f, "failed to run build"),
+            BuildError::Manifest(_) => write!(f, "failed to generate or sign manifest"),
+            BuildError::Io(_) => write!(f, "I/O error"),
+            BuildError::DuplicateMatrixLeg(ref name) => write!(f, "matrix leg {:?} is defined more than once", name),
+            BuildError::InvalidMatrixLeg(ref name) => write!(f, "matrix leg name {:?} is empty, \".\" or \"..\", or contains a path separator", name),
+        }
+    }
+}
+
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            BuildError::Download(ref err) => Some(err),
+            BuildError::ConfigRead(ref err) => Some(err),
+            BuildError::ConfigParse(ref err) => Some(err),
+            BuildError::Prepare(ref err) => Some(err),
+            BuildError::Run(ref err) => Some(err),
+            BuildError::Manifest(ref err) => Some(err),
+            BuildError::Io(ref err) => Some(err),
+            BuildError::DuplicateMatrixLeg(_) => None,
+            BuildError::InvalidMatrixLeg(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for BuildError {
+    fn from(err: io::Error) -> BuildError {
+        BuildError::Io(err)
+    }
+}
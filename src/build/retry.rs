@@ -0,0 +1,165 @@
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Configuration for retrying a fallible, network-dependent operation with
+/// exponential backoff.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RetryConfig {
+    /// Maximum number of attempts before giving up and returning the last error.
+    pub max_attempts: u32,
+    /// Delay before the first retry, in milliseconds.
+    pub initial_delay_ms: u64,
+    /// Factor the delay is multiplied by after each failed attempt.
+    pub multiplier: f64,
+    /// Upper bound on the delay between attempts, in milliseconds.
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 5,
+            initial_delay_ms: 500,
+            multiplier: 2.0,
+            max_delay_ms: 30_000,
+        }
+    }
+}
+
+/// Tracks the delay and next-attempt instant for an exponential backoff.
+struct Backoff {
+    delay: Duration,
+    next_attempt: Instant,
+}
+
+impl Backoff {
+    fn new(config: &RetryConfig) -> Backoff {
+        Backoff {
+            delay: Duration::from_millis(config.initial_delay_ms),
+            next_attempt: Instant::now(),
+        }
+    }
+
+    fn wait(&mut self) {
+        let now = Instant::now();
+        if self.next_attempt > now {
+            thread::sleep(self.next_attempt - now);
+        }
+    }
+
+    fn advance(&mut self, config: &RetryConfig) {
+        let jittered = jitter(self.delay);
+        self.next_attempt = Instant::now() + jittered;
+
+        let next_delay_ms = (self.delay.as_secs() * 1_000 + u64::from(self.delay.subsec_nanos()) / 1_000_000) as f64 * config.multiplier;
+        self.delay = Duration::from_millis((next_delay_ms as u64).min(config.max_delay_ms));
+    }
+}
+
+/// Applies up to ±25% random jitter to `delay`, to avoid a thundering herd
+/// of retrying clients all hitting a recovering remote at the same instant.
+fn jitter(delay: Duration) -> Duration {
+    let delay_ms = delay.as_secs() * 1_000 + u64::from(delay.subsec_nanos()) / 1_000_000;
+    let factor = 1.0 + (jitter_fraction() * 0.5 - 0.25);
+    Duration::from_millis((delay_ms as f64 * factor).max(0.0) as u64)
+}
+
+/// Returns a pseudo-random value in `[0, 1)` for jitter, without pulling in
+/// a `rand` dependency for a single call site.
+fn jitter_fraction() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.subsec_nanos())
+        .unwrap_or(0);
+    (nanos % 1_000_000) as f64 / 1_000_000.0
+}
+
+/// Retries `op` according to `config`, sleeping between attempts with
+/// exponential backoff and jitter, and returning the last error if `op`
+/// never succeeds within `config.max_attempts`. `max_attempts == 0` is
+/// treated as "run once, don't retry" rather than refusing to call `op` at
+/// all.
+pub fn retry<T, E, F: FnMut() -> Result<T, E>>(config: &RetryConfig, mut op: F) -> Result<T, E> {
+    let mut backoff = Backoff::new(config);
+    let attempts = config.max_attempts.max(1);
+
+    for attempt in 1..=attempts {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                if attempt >= attempts {
+                    return Err(err);
+                }
+                backoff.advance(config);
+                backoff.wait();
+            }
+        }
+    }
+
+    unreachable!("attempts is always >= 1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn fast_config(max_attempts: u32) -> RetryConfig {
+        RetryConfig { max_attempts: max_attempts, initial_delay_ms: 0, multiplier: 2.0, max_delay_ms: 0 }
+    }
+
+    #[test]
+    fn retry_returns_ok_without_retrying_on_first_success() {
+        let attempts = Cell::new(0);
+        let result = retry(&fast_config(5), || {
+            attempts.set(attempts.get() + 1);
+            Ok::<_, ()>("done")
+        });
+        assert_eq!(result, Ok("done"));
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn retry_succeeds_after_transient_failures() {
+        let attempts = Cell::new(0);
+        let result = retry(&fast_config(5), || {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 { Err(()) } else { Ok("done") }
+        });
+        assert_eq!(result, Ok("done"));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn retry_gives_up_after_max_attempts_and_returns_the_last_error() {
+        let attempts = Cell::new(0);
+        let result = retry(&fast_config(3), || {
+            attempts.set(attempts.get() + 1);
+            Err::<(), _>(attempts.get())
+        });
+        assert_eq!(result, Err(3));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn retry_treats_max_attempts_zero_as_run_once() {
+        let attempts = Cell::new(0);
+        let result = retry(&fast_config(0), || {
+            attempts.set(attempts.get() + 1);
+            Err::<(), _>("nope")
+        });
+        assert_eq!(result, Err("nope"));
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn jitter_stays_within_plus_or_minus_25_percent() {
+        let delay = Duration::from_millis(1000);
+        for _ in 0..20 {
+            let jittered = jitter(delay);
+            let jittered_ms = jittered.as_secs() * 1_000 + u64::from(jittered.subsec_nanos()) / 1_000_000;
+            assert!(jittered_ms >= 750);
+            assert!(jittered_ms <= 1250);
+        }
+    }
+}
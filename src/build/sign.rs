@@ -0,0 +1,67 @@
+use std::io;
+
+use ring::signature::{self, Ed25519KeyPair, ED25519};
+use untrusted;
+
+/// Signs `message` (the canonical manifest bytes) with the ed25519 secret
+/// key `seed`, returning a detached signature suitable for writing out as
+/// `manifest.json.sig`.
+pub fn sign(seed: &[u8], message: &[u8]) -> io::Result<Vec<u8>> {
+    let key_pair = Ed25519KeyPair::from_seed_unchecked(untrusted::Input::from(seed))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid ed25519 secret key"))?;
+
+    Ok(key_pair.sign(message).as_ref().to_vec())
+}
+
+/// Verifies a detached ed25519 `signature` over `message` against
+/// `public_key`. Returns `false` on any mismatch, never an error, since a
+/// bad signature is a verification result rather than an I/O failure.
+pub fn verify(public_key: &[u8], message: &[u8], signature_bytes: &[u8]) -> bool {
+    signature::verify(
+        &ED25519,
+        untrusted::Input::from(public_key),
+        untrusted::Input::from(message),
+        untrusted::Input::from(signature_bytes),
+    ).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: [u8; 32] = [7; 32];
+
+    fn public_key() -> Vec<u8> {
+        Ed25519KeyPair::from_seed_unchecked(untrusted::Input::from(&SEED))
+            .unwrap()
+            .public_key_bytes()
+            .to_vec()
+    }
+
+    #[test]
+    fn sign_then_verify_round_trips() {
+        let signature = sign(&SEED, b"manifest bytes").unwrap();
+        assert!(verify(&public_key(), b"manifest bytes", &signature));
+    }
+
+    #[test]
+    fn verify_rejects_a_tampered_message() {
+        let signature = sign(&SEED, b"manifest bytes").unwrap();
+        assert!(!verify(&public_key(), b"different bytes", &signature));
+    }
+
+    #[test]
+    fn verify_rejects_a_mismatched_key() {
+        let signature = sign(&SEED, b"manifest bytes").unwrap();
+        let other_key = Ed25519KeyPair::from_seed_unchecked(untrusted::Input::from(&[9u8; 32]))
+            .unwrap()
+            .public_key_bytes()
+            .to_vec();
+        assert!(!verify(&other_key, b"manifest bytes", &signature));
+    }
+
+    #[test]
+    fn sign_rejects_an_invalid_seed_length() {
+        assert!(sign(&[0u8; 4], b"manifest bytes").is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+use serde_json;
+
+use Sha384;
+
+use super::sign;
+
+/// The result of verifying a build's output directory: a hard mismatch in
+/// `fatal` means the bundle cannot be trusted, while `warnings` cover
+/// tolerated issues (an optional artifact missing from disk) that don't by
+/// themselves invalidate the build.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub fatal: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.fatal.is_empty()
+    }
+}
+
+/// Recomputes every artifact's SHA384 against `manifest.json`, alongside
+/// checking the ed25519 signature in `manifest.json.sig` with `public_key`.
+/// A missing or mismatched signature, or a hash mismatch, is fatal; a
+/// manifest entry whose artifact file is missing from disk is only a warning.
+///
+/// `manifest.json` is keyed by matrix leg name (a single-base build is just
+/// a one-leg matrix), with each leg's artifacts living under
+/// `artifacts/<leg-name>/artifacts/`.
+pub fn verify<P: AsRef<Path>>(output_dir: P, public_key: &[u8]) -> io::Result<VerifyReport> {
+    let output_dir = output_dir.as_ref();
+    let mut report = VerifyReport::default();
+
+    let manifest_bytes = fs::read(output_dir.join("manifest.json"))?;
+    let manifest: serde_json::Value = serde_json::from_slice(&manifest_bytes)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    let sig_path = output_dir.join("manifest.json.sig");
+    match fs::read(&sig_path) {
+        Ok(signature) => {
+            if !sign::verify(public_key, &manifest_bytes, &signature) {
+                report.fatal.push("manifest signature does not match manifest.json".to_string());
+            }
+        }
+        Err(err) => {
+            report.fatal.push(format!("failed to read {}: {}", sig_path.display(), err));
+        }
+    }
+
+    let legs = match manifest.as_object() {
+        Some(legs) => legs,
+        None => {
+            report.fatal.push("manifest.json is not a JSON object keyed by matrix leg".to_string());
+            return Ok(report);
+        }
+    };
+
+    for (leg_name, leg_manifest) in legs.iter() {
+        if !super::valid_leg_name(leg_name) {
+            report.fatal.push(format!("matrix leg name {:?} is empty, \".\" or \"..\", or contains a path separator", leg_name));
+            continue;
+        }
+
+        let artifacts = leg_manifest.get("artifacts").and_then(|value| value.as_object());
+        let artifacts = match artifacts {
+            Some(artifacts) => artifacts,
+            None => continue,
+        };
+
+        for (name, expected_hash) in artifacts.iter() {
+            let expected_hash = match expected_hash.as_str() {
+                Some(hash) => hash,
+                None => continue,
+            };
+
+            let artifact_path = output_dir.join("artifacts").join(leg_name).join("artifacts").join(name);
+            match File::open(&artifact_path) {
+                Ok(mut file) => {
+                    let actual_hash = Sha384::new(&mut file)?;
+                    let actual_hash_str = serde_json::to_string(&actual_hash)
+                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+                    if actual_hash_str.trim_matches('"') != expected_hash {
+                        report.fatal.push(format!("artifact {}/{} does not match its recorded SHA384", leg_name, name));
+                    }
+                }
+                Err(_) => {
+                    report.warnings.push(format!("artifact {}/{} listed in manifest is missing from {}", leg_name, name, artifact_path.display()));
+                }
+            }
+        }
+    }
+
+    Ok(report)
+}
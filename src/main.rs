@@ -2,12 +2,27 @@ extern crate buildchain;
 extern crate clap;
 extern crate serde_json;
 
+use buildchain::build;
 use buildchain::{Config, Location};
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
+use std::error::Error;
+use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::process;
 
+/// Prints `err` followed by each error in its causal chain, indented, so
+/// the original underlying failure is never hidden behind a generic
+/// wrapper message.
+fn print_error_chain(context: &str, err: &Error) {
+    eprintln!("buildchain: {}: {}", context, err);
+    let mut source = err.source();
+    while let Some(err) = source {
+        eprintln!("  caused by: {}", err);
+        source = err.source();
+    }
+}
+
 fn main() {
     let matches = App::new("buildchain")
                     .arg(Arg::with_name("config")
@@ -25,11 +40,68 @@ fn main() {
                             .long("remote")
                             .takes_value(true)
                             .help("Name of remote LXC server"))
+                    .arg(Arg::with_name("cache-dir")
+                            .long("cache-dir")
+                            .takes_value(true)
+                            .help("Directory to cache downloaded sources and build artifacts in"))
+                    .arg(Arg::with_name("secret-key")
+                            .long("secret-key")
+                            .takes_value(true)
+                            .help("Ed25519 secret key file used to sign the build manifest"))
+                    .subcommand(SubCommand::with_name("verify")
+                            .about("Verify a build output directory against its manifest")
+                            .arg(Arg::with_name("output")
+                                    .short("o")
+                                    .long("output")
+                                    .takes_value(true)
+                                    .help("Build output directory to verify"))
+                            .arg(Arg::with_name("public-key")
+                                    .long("public-key")
+                                    .takes_value(true)
+                                    .required(true)
+                                    .help("Ed25519 public key file the manifest was signed with")))
                     .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        let output_path = matches.value_of("output").unwrap_or("buildchain.out");
+        let public_key_path = matches.value_of("public-key").expect("public-key is required");
+
+        let public_key = match fs::read(public_key_path) {
+            Ok(public_key) => public_key,
+            Err(err) => {
+                eprintln!("buildchain: failed to read {}: {}", public_key_path, err);
+                process::exit(1)
+            }
+        };
+
+        let report = match build::verify(output_path, &public_key) {
+            Ok(report) => report,
+            Err(err) => {
+                eprintln!("buildchain: failed to verify {}: {}", output_path, err);
+                process::exit(1)
+            }
+        };
+
+        for warning in &report.warnings {
+            println!("buildchain: warning: {}", warning);
+        }
+
+        if report.is_ok() {
+            println!("buildchain: {} verified", output_path);
+            return;
+        }
+
+        for fatal in &report.fatal {
+            eprintln!("buildchain: {}", fatal);
+        }
+        process::exit(1)
+    }
+
     let config_path = matches.value_of("config").unwrap_or("buildchain.json");
     let output_path = matches.value_of("output").unwrap_or("buildchain.out");
     let remote_opt = matches.value_of("remote");
+    let cache_dir_opt = matches.value_of("cache-dir");
+    let secret_key_opt = matches.value_of("secret-key");
 
     let mut file = match File::open(&config_path) {
         Ok(file) => file,
@@ -64,10 +136,10 @@ fn main() {
         Location::Local
     };
 
-    match config.run(location, output_path) {
+    match config.run(location, output_path, cache_dir_opt, secret_key_opt) {
         Ok(_) => (),
         Err(err) => {
-            eprintln!("buildchain: failed to run {}: {}", config_path, err);
+            print_error_chain(&format!("failed to run {}", config_path), &err);
             process::exit(1)
         }
     }